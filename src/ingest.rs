@@ -0,0 +1,77 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+use walkdir::WalkDir;
+
+use crate::sql::Database;
+use crate::{load_gpx, Workout};
+
+/// How many parsed workouts may be buffered between the parser pool and the writer.
+const CHANNEL_CAPACITY: usize = 256;
+/// How many `load_gpx` calls may run concurrently.
+const MAX_CONCURRENT_PARSERS: usize = 8;
+
+/// Recursively collect every `*.gpx` file under `root`.
+fn find_gpx_files(root: &Path) -> Vec<PathBuf> {
+    WalkDir::new(root)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| ext.eq_ignore_ascii_case("gpx"))
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+/// Walk `root` for GPX files, parse them on a bounded blocking worker pool, and stream the
+/// resulting `Workout`s into `Database::insert_many` for batched insertion.
+///
+/// Per-file parse failures are logged and skipped rather than aborting the whole ingestion run.
+pub async fn ingest_directory(
+    root: PathBuf,
+    db: &mut Database,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let files = find_gpx_files(&root);
+    info!("Found {} GPX file(s) under {:?}", files.len(), root);
+
+    let (tx, rx) = mpsc::channel::<Workout>(CHANNEL_CAPACITY);
+    let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_PARSERS));
+
+    let mut parsers: Vec<JoinHandle<()>> = Vec::with_capacity(files.len());
+    for path in files {
+        let semaphore = semaphore.clone();
+        let tx = tx.clone();
+        parsers.push(tokio::spawn(async move {
+            let _permit = semaphore.acquire_owned().await.expect("semaphore closed");
+            let log_path = path.clone();
+            match tokio::task::spawn_blocking(move || load_gpx(path)).await {
+                Ok(Ok(Some(workout))) => {
+                    if tx.send(workout).await.is_err() {
+                        warn!("Writer task exited before {:?} could be inserted", log_path);
+                    }
+                }
+                Ok(Ok(None)) => {
+                    info!("Skipping {:?}: unrecognized activity", log_path);
+                }
+                Ok(Err(e)) => error!("Failed to parse {:?}: {}", log_path, e),
+                Err(e) => error!("Parser task for {:?} panicked: {}", log_path, e),
+            }
+        }));
+    }
+    drop(tx);
+
+    db.insert_many(rx).await?;
+
+    for parser in parsers {
+        parser.await?;
+    }
+
+    Ok(())
+}
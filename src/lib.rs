@@ -12,6 +12,10 @@ use serde_derive::{Deserialize, Serialize};
 use strum::{EnumString, EnumVariantNames, VariantNames};
 use xml::reader::{EventReader, XmlEvent};
 
+pub mod ingest;
+pub mod sql;
+pub use sql::Database;
+
 static EPSILON: f64 = 0.0000001;
 const WORKOUT_DATETIME_FMT: &str = "%Y-%m-%d-%H%M%S";
 const RECORD_DATETIME_FMT: &str = "%Y-%m-%d %H:%M:%S";
@@ -58,7 +62,7 @@ impl Record {
     fn load_data(
         &mut self,
         data: &HashMap<String, String>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         if let Some(v) = data.get("ele") {
             self.elevation = Some(v.parse::<f32>()?);
         }
@@ -86,14 +90,14 @@ impl Record {
         Ok(())
     }
 
-    fn _null_island(&self) -> Result<bool, Box<dyn std::error::Error>> {
+    fn _null_island(&self) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
         match &self.geopoint {
             Some(g) => Ok((g.lat * g.lat + g.lng * g.lng).sqrt() <= EPSILON),
             None => Ok(false),
         }
     }
 
-    pub fn validate(&self) -> Result<bool, Box<dyn std::error::Error>> {
+    pub fn validate(&self) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
         Ok(!(self._null_island()?)
             && !(self.elevation.is_none()
                 && self.timestamp.is_none()
@@ -115,22 +119,34 @@ pub struct Workout {
 }
 
 impl Workout {
-    pub fn geopoly(&self) -> Result<String, Box<dyn std::error::Error>> {
-        let coords: Vec<String> = self
+    /// Build a geopoly polygon ring from this workout's track, in the `[x,y]` (i.e.
+    /// `[lng,lat]`) vertex order geopoly requires, closed so the first and last vertex match.
+    /// Returns `Ok(None)` if the workout has no valid geopoints (e.g. an indoor workout with
+    /// no trkpt coordinates), since there is no ring to build in that case.
+    pub fn geopoly(&self) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut coords: Vec<String> = self
             .records
             .iter()
+            .filter(|record| record.validate().unwrap_or(false))
             .filter_map(|record| {
-                record.validate().ok().map(|_| match &record.geopoint {
-                    Some(g) => format!("[{},{}]", g.lat, g.lng),
-                    None => String::from("[0.0,0.0]"),
-                })
+                record
+                    .geopoint
+                    .as_ref()
+                    .map(|g| format!("[{},{}]", g.lng, g.lat))
             })
             .collect();
-        let mut result: String = "'[ ".to_owned();
-        let suffix: &str = "]'";
-        result.push_str(&coords.join(","));
-        result.push_str(suffix);
-        Ok(result)
+
+        if coords.is_empty() {
+            return Ok(None);
+        }
+
+        if coords.first() != coords.last() {
+            if let Some(first) = coords.first().cloned() {
+                coords.push(first);
+            }
+        }
+
+        Ok(Some(format!("[{}]", coords.join(","))))
     }
 }
 
@@ -147,7 +163,7 @@ lazy_static! {
     };
 }
 
-pub fn get_activity(path: &str) -> Result<Activity, Box<dyn std::error::Error>> {
+pub fn get_activity(path: &str) -> Result<Activity, Box<dyn std::error::Error + Send + Sync>> {
     if let Some(captures) = ACTIVITY_EXPR.captures(path) {
         let name = &captures[0];
         Ok(Activity::from_str(name)?)
@@ -156,7 +172,7 @@ pub fn get_activity(path: &str) -> Result<Activity, Box<dyn std::error::Error>>
     }
 }
 
-pub fn get_workout_timestamp(path: &str, regex: &str) -> Result<i64, Box<dyn std::error::Error>> {
+pub fn get_workout_timestamp(path: &str, regex: &str) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
     let re: Regex = Regex::new(regex)?;
     let d = re.find(path).ok_or("No match found")?.as_str();
     let timestamp = NaiveDateTime::parse_from_str(d, WORKOUT_DATETIME_FMT)?;
@@ -164,7 +180,7 @@ pub fn get_workout_timestamp(path: &str, regex: &str) -> Result<i64, Box<dyn std
 }
 
 
-pub fn get_record_timestamp(time: &str, regex: &str) -> Result<i64, Box<dyn std::error::Error>> {
+pub fn get_record_timestamp(time: &str, regex: &str) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
     let re: Regex = Regex::new(regex)?;
     let d = re.replace_all(time, "$y-$m-$d $H:$M:$S");
     let timestamp = NaiveDateTime::parse_from_str(&d, RECORD_DATETIME_FMT)?;
@@ -172,7 +188,7 @@ pub fn get_record_timestamp(time: &str, regex: &str) -> Result<i64, Box<dyn std:
 }
 
 #[tracing::instrument]
-pub fn load_gpx(path: PathBuf) -> Result<Option<Workout>, Box<dyn std::error::Error>> {
+pub fn load_gpx(path: PathBuf) -> Result<Option<Workout>, Box<dyn std::error::Error + Send + Sync>> {
     let path_str = path.to_str().ok_or("")?;
     let activity = get_activity(path_str)?;
     let timestamp = get_workout_timestamp(path_str, REGEX_CHARS)?;
@@ -184,20 +200,18 @@ pub fn load_gpx(path: PathBuf) -> Result<Option<Workout>, Box<dyn std::error::Er
 
             let mut records: Vec<Record> = Vec::new();
             let mut current_element = String::new();
-            let mut record = Record {
-                ..Default::default()
-            };
+            let mut current_record: Option<Record> = None;
             let parser = EventReader::new(file);
             for event in parser {
-                let mut geopoint = GeoPoint {
-                    ..Default::default()
-                };
                 match event {
                     Ok(XmlEvent::StartElement {
                         name, attributes, ..
                     }) => {
                         current_element = name.local_name;
                         if current_element.as_str() == "trkpt" {
+                            let mut geopoint = GeoPoint {
+                                ..Default::default()
+                            };
                             for attr in attributes {
                                 match attr.name.local_name.as_str() {
                                     "lat" => geopoint.lat = attr.value.parse::<f64>()?,
@@ -205,19 +219,30 @@ pub fn load_gpx(path: PathBuf) -> Result<Option<Workout>, Box<dyn std::error::Er
                                     _ => (),
                                 }
                             }
+                            current_record = Some(Record {
+                                activity: Some(activity.clone()),
+                                ds: Some(timestamp),
+                                geopoint: Some(geopoint),
+                                ..Default::default()
+                            });
                         }
                     }
                     Ok(XmlEvent::Characters(text)) => {
-                        let map = HashMap::from([(current_element.clone(), text.clone())]);
-                        record.load_data(&map)?;
+                        if let Some(record) = current_record.as_mut() {
+                            let map = HashMap::from([(current_element.clone(), text.clone())]);
+                            record.load_data(&map)?;
+                        }
+                    }
+                    Ok(XmlEvent::EndElement { name }) => {
+                        if name.local_name == "trkpt" {
+                            if let Some(record) = current_record.take() {
+                                records.push(record);
+                            }
+                        }
                     }
                     Err(e) => panic!("Error processing event: {}", e),
                     _ => (),
                 }
-                record.geopoint = Some(geopoint);
-                record.activity = Some(activity.clone());
-                record.ds = Some(timestamp);
-                records.push(record.clone());
             }
             Ok(Some(Workout {
                 activity: activity.clone(),
@@ -227,3 +252,42 @@ pub fn load_gpx(path: PathBuf) -> Result<Option<Workout>, Box<dyn std::error::Er
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn geopoly_closes_the_ring_in_lng_lat_order() {
+        let workout = Workout {
+            activity: Activity::Running,
+            timestamp: 0,
+            records: vec![
+                Record {
+                    geopoint: Some(GeoPoint { lat: 1.0, lng: 2.0 }),
+                    elevation: Some(10.0),
+                    ..Default::default()
+                },
+                Record {
+                    geopoint: Some(GeoPoint { lat: 3.0, lng: 4.0 }),
+                    elevation: Some(20.0),
+                    ..Default::default()
+                },
+            ],
+        };
+
+        let ring = workout.geopoly().unwrap().unwrap();
+        assert_eq!(ring, "[[2,1],[4,3],[2,1]]");
+    }
+
+    #[test]
+    fn geopoly_is_none_for_a_workout_with_no_valid_geopoints() {
+        let workout = Workout {
+            activity: Activity::Running,
+            timestamp: 0,
+            records: vec![],
+        };
+
+        assert!(workout.geopoly().unwrap().is_none());
+    }
+}
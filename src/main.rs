@@ -2,13 +2,11 @@ use std::num::ParseIntError;
 use std::{path::PathBuf, str::FromStr};
 
 use clap::Parser;
-use rusqlite::Connection;
 use sha256::digest;
-use tokio::sync::mpsc::channel;
 use tracing::info;
 
-mod sql;
-use workout_gpx_rs::{load_gpx, Workout};
+use workout_gpx_rs::sql::{self, Database};
+use workout_gpx_rs::{ingest, load_gpx, Workout};
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
@@ -22,6 +20,14 @@ struct Args {
     #[arg(short, long, default_value_t = false)]
     create_table: bool,
 
+    /// Path to the SQLite database file
+    #[arg(short, long, default_value = sql::DEFAULT_DB_NAME)]
+    db: String,
+
+    /// Back up the database to this path using SQLite's online backup API
+    #[arg(short, long)]
+    backup: Option<String>,
+
     /// Test hashing
     #[arg(short, long, default_value_t = false)]
     test_hash: bool,
@@ -34,7 +40,7 @@ fn decode_hex(s: &str, step: usize) -> Result<Vec<u32>, ParseIntError> {
         .collect()
 }
 
-fn test_hash() -> Result<(), Box<dyn std::error::Error>> {
+fn test_hash() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let input = "hello";
     let val = digest(input);
     let rs: Vec<u32> = decode_hex(&val, 8)?;
@@ -45,7 +51,7 @@ fn test_hash() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     tracing_subscriber::fmt::init();
 
     let args = Args::parse();
@@ -54,10 +60,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         return test_hash();
     }
 
-    let mut connection: Connection = sql::get_connection()?;
+    let mut db = Database::open(&args.db)?;
     if args.create_table {
         info!("Creating new table in SQLite database");
-        sql::create_table(&connection).await?;
+        db.create_schema()?;
     }
 
     if let Some(p) = args.path {
@@ -80,25 +86,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 workout.records.len(),
                 workout.activity
             );
-            let (tx, rx) = channel(1);
-            tx.send(workout).await?;
-            sql::insert_records(&mut connection, rx).await?;
+            db.insert_workout(&workout)?;
+        } else if path.is_dir() {
+            ingest::ingest_directory(path, &mut db).await?;
         }
+    }
 
-        // } else if path.is_dir() {
-        //     let files = path.read_dir().unwrap();
-        //     for file in files {
-        //         let path = PathBuf::from_str(file.unwrap().path().to_str().unwrap()).unwrap();
-        //         match load_gpx(path).unwrap() {
-        //             Some(w) => {
-        //                 workouts.push(w);
-        //             }
-        //             _ => {
-        //                 println!("Unable to parse record");
-        //             }
-        //         }
-        //     }
-        // }
+    if let Some(dest) = args.backup {
+        let dest_path = PathBuf::from_str(dest.as_str())?;
+        info!("Backing up database to {:?}", dest_path);
+        db.backup(&dest_path, |progress| {
+            info!(
+                "Backup progress: {}/{} pages remaining",
+                progress.remaining, progress.pagecount
+            );
+        })?;
     }
 
     Ok(())
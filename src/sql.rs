@@ -1,9 +1,31 @@
+use std::path::Path;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
 use const_format::formatcp;
-use rusqlite::{Connection, ToSql};
-use tokio::sync::mpsc::error::TryRecvError;
+pub use rusqlite::backup::Progress;
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::ErrorCode;
+use rusqlite::{params, Connection, ToSql};
 use tokio::sync::mpsc::Receiver;
-use tracing::info;
-use workout_gpx_rs::Workout;
+use tracing::{info, warn};
+
+use crate::{Activity, GeoPoint, Record, Workout};
+
+/// Number of workouts committed per transaction while draining the ingestion channel.
+const INSERT_BATCH_SIZE: usize = 100;
+/// Pages copied per backup step, with a short sleep in between so a backup of a live,
+/// exclusively-locked database doesn't starve other work.
+const BACKUP_PAGES_PER_STEP: i32 = 100;
+const BACKUP_STEP_SLEEP: Duration = Duration::from_millis(250);
+
+/// Retry tuning for opening a database that another process briefly holds locked. The
+/// PRAGMAs in [`PRAGMAS`] (in particular `locking_mode = EXCLUSIVE`) mean a concurrent
+/// invocation of this tool can see `SQLITE_BUSY`/`SQLITE_LOCKED` right at open time.
+const MAX_OPEN_ATTEMPTS: u32 = 5;
+const INITIAL_OPEN_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_OPEN_BACKOFF: Duration = Duration::from_secs(2);
 
 const PRAGMAS: [&str; 5] = [
     "PRAGMA journal_mode = OFF",
@@ -13,8 +35,14 @@ const PRAGMAS: [&str; 5] = [
     "PRAGMA temp_store = MEMORY",
 ];
 
-const DB_NAME: &str = "workouts.sqlite";
+pub const DEFAULT_DB_NAME: &str = "workouts.sqlite";
 const TABLE: &str = "workouts";
+/// Schema DDL, including the `{TABLE}_geo` `geopoly` virtual table backing
+/// [`Database::workouts_in_bbox`]/[`Database::workouts_near`]. `geopoly` is only compiled into
+/// SQLite when `SQLITE_ENABLE_GEOPOLY` was defined at build time; `libsqlite3-sys`'s `bundled`
+/// feature does not set it, so `create_schema` will fail with `no such module: geopoly` unless
+/// this crate links a SQLite built with that flag (e.g. a custom-compiled `libsqlite3-sys`, or a
+/// system SQLite built with the flag via the `rusqlite` `sqlcipher`/non-bundled linking options).
 const CREATE_TABLES: &str = formatcp!(
     "DROP TABLE IF EXISTS {};
 DROP TABLE IF EXISTS {}_records;
@@ -28,6 +56,10 @@ CREATE TABLE IF NOT EXISTS {}_records (
   elevation float,
   heartrate integer,
   temperature integer,
+  speed float,
+  course float,
+  hacc float,
+  vacc float,
   UNIQUE (wid, ds, ts)
 );
 CREATE TABLE IF NOT EXISTS {} (
@@ -37,7 +69,7 @@ CREATE TABLE IF NOT EXISTS {} (
   record_locations text
 );
 CREATE VIRTUAL TABLE {}_geo
-USING geopoly ();",
+USING geopoly (activity, ds);",
     TABLE,
     TABLE,
     TABLE,
@@ -64,8 +96,12 @@ const RECORD_SQL: &str = formatcp!(
     lng,
     elevation,
     heartrate,
-    temperature
-    ) VALUES (?, ?, ?, ?, ?, ?, ?, ?);",
+    temperature,
+    speed,
+    course,
+    hacc,
+    vacc
+    ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);",
     TABLE
 );
 const GEO_SQL: &str = formatcp!(
@@ -76,82 +112,372 @@ const GEO_SQL: &str = formatcp!(
     )  VALUES(?, ?, ?);",
     TABLE
 );
+const SELECT_RECORDS_SQL: &str = formatcp!(
+    "SELECT ds, ts, lat, lng, elevation, heartrate, temperature, speed, course, hacc, vacc
+    FROM {}_records WHERE wid = ?;",
+    TABLE
+);
+const SELECT_GEO_OVERLAP_SQL: &str = formatcp!(
+    "SELECT ds FROM {}_geo WHERE geopoly_overlap(_shape, ?);",
+    TABLE
+);
 
-pub fn get_connection() -> Result<Connection, Box<dyn std::error::Error>> {
-    let conn: Connection = Connection::open(DB_NAME)?;
-    info!("Executing {} PRAGMA statements", PRAGMAS.len());
-    conn.execute_batch(&PRAGMAS.join("; ")).expect("PRAGMAS");
-    info!("Connection created");
-    Ok(conn)
+/// Whether `err` represents transient lock contention (`SQLITE_BUSY`/`SQLITE_LOCKED`) as
+/// opposed to a permanent failure (bad path, corrupt file, etc.) that retrying won't fix.
+fn is_lock_contention(err: &rusqlite::Error) -> bool {
+    matches!(
+        err.sqlite_error_code(),
+        Some(ErrorCode::DatabaseBusy) | Some(ErrorCode::DatabaseLocked)
+    )
 }
 
-pub async fn create_table(conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
-    // info!("Removing existing db file: {}", DB_NAME);
-    // let _ = fs::remove_file(DB_NAME);
-    info!("Executing table creation");
-    conn.execute_batch(CREATE_TABLES)?;
-    Ok(())
+/// Build a closed geopoly rectangle ring in `[lng,lat]` vertex order for a bounding box.
+fn bbox_polygon(min_lat: f64, min_lng: f64, max_lat: f64, max_lng: f64) -> String {
+    let ring = [
+        (min_lng, min_lat),
+        (max_lng, min_lat),
+        (max_lng, max_lat),
+        (min_lng, max_lat),
+        (min_lng, min_lat),
+    ];
+    let points: Vec<String> = ring
+        .iter()
+        .map(|(x, y)| format!("[{},{}]", x, y))
+        .collect();
+    format!("[{}]", points.join(","))
 }
 
-pub async fn insert_records(
-    conn: &mut Connection,
-    mut workouts: Receiver<Workout>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    loop {
-        let tx = conn.transaction().unwrap();
+/// Owns the SQLite connection backing a workouts database: schema creation, single-workout
+/// inserts, and batched inserts off an ingestion channel all go through here.
+pub struct Database {
+    conn: Connection,
+}
+
+impl Database {
+    /// Open (or create) the database at `path`, apply the bulk-insert PRAGMAs, and retry
+    /// with exponential backoff if another process briefly holds the database locked.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let path = path.as_ref();
+        let mut backoff = INITIAL_OPEN_BACKOFF;
+        for attempt in 1..=MAX_OPEN_ATTEMPTS {
+            match Self::try_open(path) {
+                Ok(db) => return Ok(db),
+                Err(e) if is_lock_contention(&e) && attempt < MAX_OPEN_ATTEMPTS => {
+                    warn!(
+                        "Database at {:?} is locked (attempt {}/{}), retrying in {:?}",
+                        path, attempt, MAX_OPEN_ATTEMPTS, backoff
+                    );
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_OPEN_BACKOFF);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        unreachable!("loop always returns by the last attempt")
+    }
+
+    fn try_open(path: &Path) -> Result<Self, rusqlite::Error> {
+        let conn = Connection::open(path)?;
+        info!("Executing {} PRAGMA statements", PRAGMAS.len());
+        conn.execute_batch(&PRAGMAS.join("; "))?;
+        info!("Connection created");
+        Ok(Self { conn })
+    }
+
+    pub fn create_schema(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        info!("Executing table creation");
+        self.conn.execute_batch(CREATE_TABLES).map_err(|e| {
+            format!(
+                "failed to create schema: {e} (the {TABLE}_geo table needs a SQLite build with \
+                 SQLITE_ENABLE_GEOPOLY; see the comment on CREATE_TABLES)"
+            )
+        })?;
+        Ok(())
+    }
+
+    pub fn insert_workout(
+        &mut self,
+        workout: &Workout,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.insert_batch(std::slice::from_ref(workout))
+    }
+
+    /// Fetch stored workouts, optionally narrowed by a raw SQL `WHERE` clause (e.g.
+    /// `"activity = 'Running'"`). Records are not populated; fetch them separately with
+    /// [`Database::fetch_records`].
+    pub fn fetch_workouts(
+        &self,
+        criteria: Option<&str>,
+    ) -> Result<Vec<Workout>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut query = format!("SELECT activity, ds FROM {}", TABLE);
+        if let Some(clause) = criteria {
+            query.push_str(" WHERE ");
+            query.push_str(clause);
+        }
+        let mut stmt = self.conn.prepare(&query)?;
+        let workouts = stmt
+            .query_map([], |row| {
+                let activity: String = row.get(0)?;
+                Ok(Workout {
+                    activity: Activity::from_str(&activity).unwrap_or(Activity::Unknown),
+                    timestamp: row.get(1)?,
+                    records: Vec::new(),
+                })
+            })?
+            .collect::<Result<Vec<Workout>, rusqlite::Error>>()?;
+        Ok(workouts)
+    }
+
+    /// Fetch the records belonging to the workout identified by `wid`.
+    pub fn fetch_records(
+        &self,
+        wid: i64,
+    ) -> Result<Vec<Record>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut stmt = self.conn.prepare(SELECT_RECORDS_SQL)?;
+        let records = stmt
+            .query_map(params![wid], |row| {
+                let lat: f64 = row.get(2)?;
+                let lng: f64 = row.get(3)?;
+                // `ts` has TEXT affinity, so the integer timestamp comes back as text.
+                let ts: Option<String> = row.get(1)?;
+                Ok(Record {
+                    ds: row.get(0)?,
+                    timestamp: ts.and_then(|ts| ts.parse().ok()),
+                    geopoint: Some(GeoPoint { lat, lng }),
+                    elevation: row.get(4)?,
+                    heartrate: row.get(5)?,
+                    temperature: row.get(6)?,
+                    speed: row.get(7)?,
+                    course: row.get(8)?,
+                    hAcc: row.get(9)?,
+                    vAcc: row.get(10)?,
+                    ..Default::default()
+                })
+            })?
+            .collect::<Result<Vec<Record>, rusqlite::Error>>()?;
+        Ok(records)
+    }
+
+    /// Workout timestamps whose track overlaps the given lat/lng bounding box.
+    pub fn workouts_in_bbox(
+        &self,
+        min_lat: f64,
+        min_lng: f64,
+        max_lat: f64,
+        max_lng: f64,
+    ) -> Result<Vec<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        let polygon = bbox_polygon(min_lat, min_lng, max_lat, max_lng);
+        self.geo_overlap(&polygon)
+    }
+
+    /// Workout timestamps whose track overlaps a `radius`-degree bounding box around
+    /// `(lat, lng)`.
+    pub fn workouts_near(
+        &self,
+        lat: f64,
+        lng: f64,
+        radius: f64,
+    ) -> Result<Vec<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        self.workouts_in_bbox(lat - radius, lng - radius, lat + radius, lng + radius)
+    }
+
+    /// Copy this (possibly live) database to `dest` using SQLite's online backup API,
+    /// a page range at a time, reporting progress via `progress` after each step. Safe to
+    /// run against a database opened with `journal_mode = OFF` and exclusive locking, since
+    /// a plain file copy of such a database could capture a half-written snapshot.
+    pub fn backup(
+        &self,
+        dest: &Path,
+        mut progress: impl FnMut(Progress),
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut dest_conn = Connection::open(dest)?;
+        let backup = Backup::new(&self.conn, &mut dest_conn)?;
+        loop {
+            let step_result = backup.step(BACKUP_PAGES_PER_STEP)?;
+            progress(backup.progress());
+            match step_result {
+                StepResult::Done => break,
+                StepResult::More => thread::sleep(BACKUP_STEP_SLEEP),
+                StepResult::Busy | StepResult::Locked => thread::sleep(BACKUP_STEP_SLEEP),
+                // `StepResult` is #[non_exhaustive]; treat anything rusqlite adds later the
+                // same as `More` rather than failing to compile on upgrade.
+                _ => thread::sleep(BACKUP_STEP_SLEEP),
+            }
+        }
+        Ok(())
+    }
+
+    fn geo_overlap(
+        &self,
+        polygon: &str,
+    ) -> Result<Vec<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut stmt = self.conn.prepare(SELECT_GEO_OVERLAP_SQL)?;
+        let ids = stmt
+            .query_map(params![polygon], |row| row.get::<_, i64>(0))?
+            .collect::<Result<Vec<i64>, rusqlite::Error>>()?;
+        Ok(ids)
+    }
+
+    /// Drain `workouts` until the channel is disconnected, committing one transaction per
+    /// `INSERT_BATCH_SIZE` workouts so a large directory ingest doesn't hold a single
+    /// unbounded transaction open.
+    pub async fn insert_many(
+        &mut self,
+        mut workouts: Receiver<Workout>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut batch: Vec<Workout> = Vec::with_capacity(INSERT_BATCH_SIZE);
+        while let Some(workout) = workouts.recv().await {
+            batch.push(workout);
+            if batch.len() >= INSERT_BATCH_SIZE {
+                self.insert_batch(&batch)?;
+                batch.clear();
+            }
+        }
+        info!("The receiver channel has been closed");
+        if !batch.is_empty() {
+            self.insert_batch(&batch)?;
+        }
+        Ok(())
+    }
+
+    fn insert_batch(
+        &mut self,
+        workouts: &[Workout],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let tx = self.conn.transaction().unwrap();
         {
             let mut stmt_record = tx.prepare_cached(RECORD_SQL)?;
             let mut stmt_geo = tx.prepare_cached(GEO_SQL)?;
             let mut stmt_workout = tx.prepare_cached(WORKOUT_SQL)?;
 
-            match workouts.try_recv() {
-                Ok(w) => {
-                    let activity = w.activity.to_string();
-                    let geopoly = w.geopoly();
-                    let row_values: Vec<&dyn ToSql> = vec![];
-                    for record in w.records {
-                        if record.validate()? {
-                            let mut row_values: Vec<&dyn ToSql> = Vec::new();
-                            let (lat, lng) = record
-                                .geopoint
-                                .as_ref()
-                                .map_or((0.0, 0.0), |g| (g.lat, g.lng));
-                            row_values.push(&record.ds as &dyn ToSql);
-                            row_values.push(&record.timestamp as &dyn ToSql);
-                            row_values.push(&lat as &dyn ToSql);
-                            row_values.push(&lng as &dyn ToSql);
-                            row_values.push(&record.elevation as &dyn ToSql);
-                            row_values.push(&record.heartrate as &dyn ToSql);
-                            row_values.push(&record.temperature as &dyn ToSql);
-                        }
-                    }
-                    stmt_record.execute(&*row_values)?;
-
-                    match geopoly {
-                        Ok(coords) => {
-                            let row_values: Vec<&dyn ToSql> = vec![
-                                &activity as &dyn ToSql,
-                                &w.timestamp as &dyn ToSql,
-                                &coords as &dyn ToSql,
-                            ];
-                            stmt_geo.execute(&*row_values)?;
-                        }
-                        Err(_) => {
-                            return Err("Unable to insert geospatial coordinates".into());
-                        }
+            for w in workouts {
+                let activity = w.activity.to_string();
+                let geopoly = w.geopoly();
+
+                let record_locations: String = w
+                    .records
+                    .iter()
+                    .filter_map(|record| record.timestamp)
+                    .map(|ts| ts.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let workout_row_values: Vec<&dyn ToSql> = vec![
+                    &w.timestamp as &dyn ToSql,
+                    &activity as &dyn ToSql,
+                    &w.timestamp as &dyn ToSql,
+                    &record_locations as &dyn ToSql,
+                ];
+                stmt_workout.execute(&*workout_row_values)?;
+
+                for record in &w.records {
+                    if record.validate()? {
+                        let wid = record.ds.unwrap_or(w.timestamp);
+                        let (lat, lng) = record
+                            .geopoint
+                            .as_ref()
+                            .map_or((0.0, 0.0), |g| (g.lat, g.lng));
+                        let mut row_values: Vec<&dyn ToSql> = Vec::new();
+                        row_values.push(&wid as &dyn ToSql);
+                        row_values.push(&record.ds as &dyn ToSql);
+                        row_values.push(&record.timestamp as &dyn ToSql);
+                        row_values.push(&lat as &dyn ToSql);
+                        row_values.push(&lng as &dyn ToSql);
+                        row_values.push(&record.elevation as &dyn ToSql);
+                        row_values.push(&record.heartrate as &dyn ToSql);
+                        row_values.push(&record.temperature as &dyn ToSql);
+                        row_values.push(&record.speed as &dyn ToSql);
+                        row_values.push(&record.course as &dyn ToSql);
+                        row_values.push(&record.hAcc as &dyn ToSql);
+                        row_values.push(&record.vAcc as &dyn ToSql);
+                        stmt_record.execute(&*row_values)?;
                     }
                 }
-                Err(TryRecvError::Empty) => {
-                    info!("No more records to process!");
-                    break;
-                }
-                Err(TryRecvError::Disconnected) => {
-                    info!("The receiver channel has been closed");
-                    break;
+
+                match geopoly {
+                    Ok(Some(coords)) => {
+                        let row_values: Vec<&dyn ToSql> = vec![
+                            &activity as &dyn ToSql,
+                            &w.timestamp as &dyn ToSql,
+                            &coords as &dyn ToSql,
+                        ];
+                        stmt_geo.execute(&*row_values)?;
+                    }
+                    Ok(None) => {
+                        info!(
+                            "Skipping geospatial insert for workout {}: no valid geopoints",
+                            w.timestamp
+                        );
+                    }
+                    Err(_) => {
+                        return Err("Unable to insert geospatial coordinates".into());
+                    }
                 }
             }
         }
         tx.commit().unwrap();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_workout(timestamp: i64) -> Workout {
+        Workout {
+            activity: Activity::Running,
+            timestamp,
+            records: vec![Record {
+                geopoint: Some(GeoPoint {
+                    lat: 40.0,
+                    lng: -73.0,
+                }),
+                elevation: Some(10.0),
+                ..Default::default()
+            }],
+        }
+    }
+
+    #[test]
+    fn insert_workout_round_trips_through_fetch_workouts() {
+        let mut db = Database::open(":memory:").expect("open in-memory db");
+        db.create_schema().expect("create schema");
+
+        let workout = sample_workout(1_700_000_000);
+        db.insert_workout(&workout).expect("insert workout");
+
+        let fetched = db.fetch_workouts(None).expect("fetch workouts");
+        assert_eq!(fetched.len(), 1);
+        assert_eq!(fetched[0].timestamp, workout.timestamp);
+        assert_eq!(
+            fetched[0].activity.to_string(),
+            workout.activity.to_string()
+        );
+    }
+
+    #[test]
+    fn bbox_polygon_is_a_closed_lng_lat_ring() {
+        let ring = bbox_polygon(10.0, 20.0, 11.0, 21.0);
+        assert_eq!(ring, "[[20,10],[21,10],[21,11],[20,11],[20,10]]");
+    }
+
+    #[test]
+    fn is_lock_contention_detects_busy_and_locked_but_not_other_errors() {
+        let busy = rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+            None,
+        );
+        let locked = rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_LOCKED),
+            None,
+        );
+        let constraint = rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CONSTRAINT),
+            None,
+        );
+
+        assert!(is_lock_contention(&busy));
+        assert!(is_lock_contention(&locked));
+        assert!(!is_lock_contention(&constraint));
     }
-    Ok(())
 }